@@ -1,21 +1,59 @@
 #![warn(clippy::pedantic)]
 #![allow(clippy::default_trait_access)]
 #![allow(clippy::doc_markdown)]
+#![allow(clippy::struct_excessive_bools)]
 
 use anyhow::{anyhow, Context, Result};
 use argh::FromArgs;
-use nanoserde::{DeBin, SerBin};
-use std::fs;
-use std::path::PathBuf;
-use std::process::Command;
+use attohttpc::{header, Response};
+use nanoserde::{DeBin, DeJson, SerBin, SerJson};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{borrow::Cow, env};
+use url::Url;
 
 /// Display missed NixOS channel updates.
 #[derive(FromArgs)]
 struct Args {
     /// the NixOS channel to retrieve updates from
     #[argh(positional)]
-    channel: String,
+    channel: Option<String>,
+
+    /// the nixpkgs branch on GitHub to retrieve updates from instead of a channel
+    #[argh(option)]
+    nixpkgs_branch: Option<String>,
+
+    /// a file containing the latest revision to compare against, in the same format as a
+    /// channel's git-revision file
+    #[argh(option)]
+    remote_rev_file: Option<PathBuf>,
+
+    /// the NixOS channel to retrieve updates from when the primary channel returns an error
+    #[argh(option)]
+    channel_fallback: Option<String>,
+
+    /// allow requests to be made over plain HTTP
+    #[argh(switch)]
+    allow_http: bool,
+
+    /// print the URL of the channel's latest Nix expressions tarball instead of the update status
+    #[argh(switch)]
+    emit_nix_path: bool,
+
+    /// end the output with a NUL byte instead of a newline
+    #[argh(switch)]
+    output_nul_terminated: bool,
+
+    /// compare the latest channel version against the given system generation instead of the
+    /// current system. The state file is left untouched unless a state file name is given
+    #[argh(option)]
+    diff_since_generation: Option<u32>,
 
     /// the message to display when the system is synced to the latest channel version
     #[argh(option, short = 's')]
@@ -25,109 +63,676 @@ struct Args {
     /// Use "$" to indicate the number of missed updates
     #[argh(option, short = 'u')]
     unsynced_message: Option<String>,
+
+    /// the message to display when the system is out of sync, but no updates have been missed.
+    /// Defaults to the synced message
+    #[argh(option)]
+    format_count_zero: Option<String>,
+
+    /// text to prepend to the displayed message
+    #[argh(option)]
+    output_prefix: Option<String>,
+
+    /// text to append to the displayed message
+    #[argh(option)]
+    output_suffix: Option<String>,
+
+    /// pad or truncate the displayed message to exactly this many characters
+    #[argh(option)]
+    output_width: Option<usize>,
+
+    /// load the existing state file for display, but never write to it
+    #[argh(switch)]
+    state_readonly: bool,
+
+    /// the name of the state file to use. Must end in ".bin"
+    #[argh(option)]
+    state_file_name: Option<String>,
+
+    /// the permissions to create the state directory with, in octal. Must be between 700 and
+    /// 777. Defaults to 700
+    #[argh(option, default = "0o700", from_str_fn(parse_state_dir_mode))]
+    state_dir_mode: u32,
+
+    /// make sure the state directory is owned by the current user and is not writable by
+    /// anyone else before running
+    #[argh(switch)]
+    state_permissions_check: bool,
+
+    /// print a warning instead of returning an error when --state-permissions-check fails
+    #[argh(switch)]
+    warn_only: bool,
+
+    /// a URL to send a GET request to after every successful check
+    #[argh(option)]
+    healthcheck_url: Option<String>,
+
+    /// a file to write the displayed message to after every check
+    #[argh(option)]
+    summary_file: Option<PathBuf>,
+
+    /// replace "{{channel_last_updated}}" in messages with the date the channel last changed
+    #[argh(switch)]
+    channel_last_updated: bool,
+
+    /// a file to mirror the state to in JSON form every time the state is saved
+    #[argh(option)]
+    write_state_json: Option<PathBuf>,
+
+    /// replace "{{auto_upgrade}}" in messages with whether system.autoUpgrade is enabled
+    #[argh(switch)]
+    auto_upgrade_check: bool,
+
+    /// a shell command to run before checking for updates. If it exits with a nonzero status,
+    /// the last known state is displayed instead
+    #[argh(option)]
+    check_connectivity_cmd: Option<String>,
+
+    /// the minimum number of seconds between checks. When ran again sooner, the last known
+    /// state is displayed instead
+    #[argh(option)]
+    min_poll_interval: Option<u64>,
+
+    /// only display the synced message when the system has just become synced
+    #[argh(switch)]
+    announce_synced: bool,
+
+    /// the number of seconds since the last successful check before the stale indicator is
+    /// added to the message. Defaults to 3600
+    #[argh(option, default = "3600")]
+    last_check_age_warning: u64,
+
+    /// the text to append to the message when the last successful check is too old.
+    /// Defaults to " [stale]"
+    #[argh(option, default = "String::from(\" [stale]\")")]
+    stale_indicator: String,
+
+    /// append an ANSI reset code to the message, so colors used in it don't carry over to
+    /// any text that follows
+    #[argh(switch)]
+    ansi_reset: bool,
+
+    /// when the channel returns an empty revision, print a warning and display the last known
+    /// state instead of returning an error
+    #[argh(switch)]
+    channel_warn_on_empty: bool,
+
+    /// replace "{{percentage}}" in messages with a rough estimate of how far behind the system
+    /// is, based on the number of nixpkgs commits made per day
+    #[argh(switch)]
+    percentage: bool,
+
+    /// the estimated number of nixpkgs commits made per month, used by --percentage.
+    /// Defaults to 2000
+    #[argh(option, default = "2000")]
+    commits_per_month: u32,
+
+    /// display only an icon for each state instead of a message. Takes the synced, unsynced,
+    /// and error icons separated by spaces
+    #[argh(option, from_str_fn(parse_status_icons))]
+    status_icon: Option<StatusIcons>,
+}
+
+fn parse_state_dir_mode(value: &str) -> Result<u32, String> {
+    let digits = value.strip_prefix("0o").unwrap_or(value);
+
+    let mode = u32::from_str_radix(digits, 8)
+        .map_err(|_| format!("{value} is not a valid octal number"))?;
+
+    if !(0o700..=0o777).contains(&mode) {
+        return Err("mode must be between 700 and 777".into());
+    }
+
+    Ok(mode)
+}
+
+struct StatusIcons {
+    synced: String,
+    unsynced: String,
+    error: String,
+}
+
+fn parse_status_icons(value: &str) -> Result<StatusIcons, String> {
+    let icons = value.split_whitespace().collect::<Vec<_>>();
+
+    match icons.as_slice() {
+        [synced, unsynced, error] => Ok(StatusIcons {
+            synced: (*synced).to_string(),
+            unsynced: (*unsynced).to_string(),
+            error: (*error).to_string(),
+        }),
+        _ => Err("expected a synced, unsynced, and error icon separated by spaces".into()),
+    }
 }
 
+const ANSI_RESET: &str = "\x1b[0m";
+
 fn main() -> Result<()> {
-    let args: Args = argh::from_env();
-
-    match UpdateState::determine_system_state(args.channel) {
-        Ok(state) => {
-            let msg = match state {
-                UpdateState::Synced => args
-                    .synced_message
-                    .map_or_else(|| "synced".into(), Cow::Owned),
-                UpdateState::Unsynced(missed, _) => args
-                    .unsynced_message
-                    .map_or_else(
-                        || format!("unsynced ({})", missed),
-                        |msg| msg.replace("$", &missed.to_string()),
-                    )
-                    .into(),
-            };
+    let mut args: Args = argh::from_env();
+
+    let error_msg = args
+        .status_icon
+        .as_ref()
+        .map_or("error", |icons| &icons.error)
+        .to_string();
+
+    if let Some(icons) = &args.status_icon {
+        if args.synced_message.is_some() || args.unsynced_message.is_some() {
+            return Err(anyhow!(
+                "status icons cannot be used with a synced or unsynced message"
+            ));
+        }
+
+        args.synced_message = Some(icons.synced.clone());
+        args.unsynced_message = Some(icons.unsynced.clone());
+    }
+
+    if !args.allow_http {
+        require_https(&args)?;
+    }
+
+    let source = RemoteSource::from_args(&args)?;
+    let terminator = if args.output_nul_terminated {
+        '\0'
+    } else {
+        '\n'
+    };
+
+    if args.emit_nix_path {
+        let url = nix_path(&source, args.allow_http)?;
+        print!("{url}{terminator}");
+        return Ok(());
+    }
+
+    let state_opts = StateOptions::from_args(&args)?;
+
+    if args.state_permissions_check {
+        if let Err(err) = State::check_dir_permissions(&state_opts) {
+            if !args.warn_only {
+                return Err(err);
+            }
 
-            println!("{}", msg);
+            eprintln!("warning: {err:#}");
+        }
+    }
+
+    match check_state(&args, &source, &state_opts) {
+        Ok(Check {
+            state,
+            became_synced,
+        }) => {
+            if args.announce_synced && state.status == UpdateState::Synced && !became_synced {
+                return Ok(());
+            }
+
+            let msg = format_message(&args, &state);
+
+            if let Some(path) = &args.summary_file {
+                write_summary(path, &msg);
+            }
+
+            print!("{msg}{terminator}");
             Ok(())
         }
         Err(err) => {
-            println!("error");
+            if let Some(path) = &args.summary_file {
+                write_summary(path, &error_msg);
+            }
+
+            print!("{error_msg}{terminator}");
             Err(err)
         }
     }
 }
 
+/// The result of checking the system state.
+struct Check {
+    state: State,
+    /// Whether the system went from being unsynced to synced during this check.
+    became_synced: bool,
+}
+
+impl Check {
+    fn cached(opts: &StateOptions) -> Self {
+        Self {
+            state: State::load(opts).unwrap_or_default(),
+            became_synced: false,
+        }
+    }
+}
+
+fn format_message(args: &Args, state: &State) -> String {
+    let synced_msg = || args.synced_message.as_deref().unwrap_or("synced");
+
+    let msg: Cow<str> = match &state.status {
+        UpdateState::Synced => synced_msg().into(),
+        UpdateState::Unsynced(0, _) => args
+            .format_count_zero
+            .as_deref()
+            .unwrap_or_else(synced_msg)
+            .into(),
+        UpdateState::Unsynced(missed, _) => match &args.unsynced_message {
+            Some(msg) => msg.replace('$', &missed.to_string()).into(),
+            None => format!("unsynced ({missed})").into(),
+        },
+    };
+
+    let msg = if args.channel_last_updated {
+        let date = iso_date(state.remote_rev_changed_at);
+        msg.replace("{channel_last_updated}", &date).into()
+    } else {
+        msg
+    };
+
+    let msg = if args.auto_upgrade_check {
+        let enabled = auto_upgrade_enabled().to_string();
+        msg.replace("{auto_upgrade}", &enabled).into()
+    } else {
+        msg
+    };
+
+    let msg = if args.percentage {
+        let percentage = percentage_behind(state.status.missed_updates(), args.commits_per_month);
+        msg.replace("{percentage}", &format!("{percentage:.1}"))
+            .into()
+    } else {
+        msg
+    };
+
+    let age = unix_timestamp().saturating_sub(state.last_check_at);
+
+    // Icons are meant to be displayed on their own
+    let msg = if age > args.last_check_age_warning && args.status_icon.is_none() {
+        format!("{msg}{}", args.stale_indicator).into()
+    } else {
+        msg
+    };
+
+    let prefix = args.output_prefix.as_deref().unwrap_or_default();
+    let suffix = args.output_suffix.as_deref().unwrap_or_default();
+
+    let mut msg = format!("{prefix}{msg}{suffix}");
+
+    if let Some(width) = args.output_width {
+        msg = fit_to_width(&msg, width);
+    }
+
+    if args.ansi_reset {
+        msg.push_str(ANSI_RESET);
+    }
+
+    msg
+}
+
+fn nix_path(source: &RemoteSource, allow_http: bool) -> Result<Url> {
+    let name = match source {
+        RemoteSource::Channel { name, .. } => name,
+        RemoteSource::NixpkgsBranch(_) | RemoteSource::RevisionFile(_) => {
+            return Err(anyhow!("the Nix path can only be emitted for a channel"))
+        }
+    };
+
+    channel_tarball_url(name, allow_http).context("getting channel tarball URL")
+}
+
+/// Ensures that no request will be made over plain HTTP.
+fn require_https(args: &Args) -> Result<()> {
+    if let Some(url) = &args.healthcheck_url {
+        if !url.starts_with("https://") {
+            return Err(anyhow!(
+                "refusing to use non-HTTPS healthcheck URL without --allow-http: {}",
+                url
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_state(args: &Args, source: &RemoteSource, opts: &StateOptions) -> Result<Check> {
+    if let Some(interval) = args.min_poll_interval {
+        let check = Check::cached(opts);
+
+        if unix_timestamp().saturating_sub(check.state.last_check_at) < interval {
+            return Ok(check);
+        }
+    }
+
+    if let Some(cmd) = &args.check_connectivity_cmd {
+        if !connectivity_check_passes(cmd)? {
+            return Ok(Check::cached(opts));
+        }
+    }
+
+    let check = match State::determine_system_state(
+        source,
+        args.diff_since_generation,
+        args.allow_http,
+        opts,
+    ) {
+        Ok(check) => check,
+        Err(err) if args.channel_warn_on_empty && err.is::<EmptyRevisionError>() => {
+            eprintln!("warning: {err:#}");
+            return Ok(Check::cached(opts));
+        }
+        Err(err) => return Err(err),
+    };
+
+    if let Some(url) = &args.healthcheck_url {
+        if let Err(err) = ping_healthcheck(url, args.allow_http) {
+            eprintln!("warning: failed to ping healthcheck URL: {err:#}");
+        }
+    }
+
+    Ok(check)
+}
+
+fn connectivity_check_passes(cmd: &str) -> Result<bool> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .with_context(|| anyhow!("failed to run connectivity check command: {}", cmd))?;
+
+    Ok(status.success())
+}
+
+fn ping_healthcheck(url: &str, allow_http: bool) -> Result<()> {
+    let (_, resp) = get_following_redirects(Url::parse(url)?, allow_http)?;
+
+    if !resp.is_success() {
+        return Err(anyhow!("bad response: {}", resp.status()));
+    }
+
+    Ok(())
+}
+
+/// Checks if `system.autoUpgrade.enable` is set by looking for the timer it creates.
+fn auto_upgrade_enabled() -> bool {
+    Path::new("/etc/systemd/system/nixos-upgrade.timer").exists()
+}
+
+/// Roughly estimates how far behind the system is as a percentage of a day's worth of
+/// nixpkgs commits.
+fn percentage_behind(missed: MissedUpdates, commits_per_month: u32) -> f64 {
+    let commits_per_day = f64::from(commits_per_month) / 30.0;
+    (f64::from(missed) / commits_per_day * 100.0).min(100.0)
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Formats a Unix timestamp as an ISO 8601 date in UTC.
+fn iso_date(timestamp: u64) -> String {
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = timestamp / 86_400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn fit_to_width(msg: &str, width: usize) -> String {
+    let truncated = msg.chars().take(width).collect::<String>();
+    format!("{truncated:<width$}")
+}
+
+/// Writes `msg` to the summary file. Failures only print a warning, since the summary file
+/// is a side output of the check.
+fn write_summary(path: &Path, msg: &str) {
+    if let Err(err) = write_atomic(path, msg.as_bytes()) {
+        eprintln!(
+            "warning: failed to write summary file to {}: {:#}",
+            path.display(),
+            err
+        );
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it over `path`,
+/// so readers never see a partially written file.
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+
+    let tmp_path = path.with_file_name(tmp_name);
+
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
 type MissedUpdates = u32;
 type Revision = String;
 
-#[derive(SerBin, DeBin)]
+#[derive(SerBin, DeBin, Default, Clone, PartialEq, Debug)]
 enum UpdateState {
+    #[default]
     Synced,
     Unsynced(MissedUpdates, Revision),
 }
 
 impl UpdateState {
-    const DEFAULT_FILE_NAME: &'static str = "state.bin";
+    fn missed_updates(&self) -> MissedUpdates {
+        match self {
+            Self::Synced => 0,
+            Self::Unsynced(missed, _) => *missed,
+        }
+    }
+
+    /// Returns the status after seeing `remote_rev` as the latest remote revision.
+    fn next(&self, is_unsynced: bool, remote_rev: &str) -> Self {
+        match self {
+            Self::Synced if is_unsynced => Self::Unsynced(1, remote_rev.into()),
+            Self::Unsynced(missed, last_rev) if is_unsynced && remote_rev != last_rev => {
+                Self::Unsynced(missed.saturating_add(1), remote_rev.into())
+            }
+            Self::Unsynced(_, _) if !is_unsynced => Self::Synced,
+            Self::Synced | Self::Unsynced(_, _) => self.clone(),
+        }
+    }
+}
+
+#[derive(SerBin, DeBin, Default, PartialEq, Debug)]
+struct State {
+    status: UpdateState,
+    /// The last seen revision of the remote source, regardless of sync status.
+    remote_rev: Revision,
+    /// Unix timestamp of when `remote_rev` last changed, or when tracking began.
+    remote_rev_changed_at: u64,
+    /// Unix timestamp of the last successful check against the remote source.
+    last_check_at: u64,
+}
+
+#[derive(SerJson)]
+struct JsonState {
+    synced: bool,
+    missed_updates: MissedUpdates,
+    remote_rev: Revision,
+    remote_rev_changed_at: u64,
+    last_check_at: u64,
+}
 
-    fn determine_system_state<S>(channel: S) -> Result<Self>
-    where
-        S: AsRef<str>,
-    {
-        let remote_rev =
-            remote_system_revision(channel).context("getting latest channel version")?;
-        let current_rev = current_system_revision().context("getting current system version")?;
+impl State {
+    fn determine_system_state(
+        source: &RemoteSource,
+        generation: Option<u32>,
+        allow_http: bool,
+        opts: &StateOptions,
+    ) -> Result<Check> {
+        let remote_rev = source
+            .latest_revision(allow_http)
+            .context("getting latest channel version")?;
+        let current_rev =
+            current_system_revision(generation).context("getting current system version")?;
 
         let is_unsynced = remote_rev != current_rev;
 
-        let mut state = Self::load().unwrap_or_default();
+        let state = Self::load(opts).unwrap_or_default();
+        let now = unix_timestamp();
 
-        match &state {
-            Self::Synced if is_unsynced => {
-                state = Self::Unsynced(1, remote_rev);
-                state.save()?;
-            }
-            Self::Unsynced(missed, last_rev) if is_unsynced && remote_rev != *last_rev => {
-                state = Self::Unsynced(missed + 1, remote_rev);
-                state.save()?;
-            }
-            Self::Unsynced(_, _) if !is_unsynced => {
-                state = Self::Synced;
-                state.save()?;
-            }
-            Self::Synced | Self::Unsynced(_, _) => (),
+        let status = state.status.next(is_unsynced, &remote_rev);
+
+        let remote_rev_changed_at = if remote_rev == state.remote_rev {
+            state.remote_rev_changed_at
+        } else {
+            now
+        };
+
+        let new_state = Self {
+            status,
+            remote_rev,
+            remote_rev_changed_at,
+            last_check_at: now,
+        };
+
+        if new_state != state && !opts.readonly {
+            new_state.save(opts)?;
         }
 
-        Ok(state)
+        let became_synced =
+            state.status != UpdateState::Synced && new_state.status == UpdateState::Synced;
+
+        Ok(Check {
+            state: new_state,
+            became_synced,
+        })
     }
 
-    fn load() -> Result<Self> {
-        let mut path = Self::save_dir();
-        path.push(Self::DEFAULT_FILE_NAME);
+    fn load(opts: &StateOptions) -> Result<Self> {
+        let path = opts.path();
 
-        let bytes = fs::read_to_string(&path)
+        let bytes = fs::read(&path)
             .with_context(|| anyhow!("failed to read state file at {}", path.display()))?;
 
-        let state = DeBin::deserialize_bin(bytes.as_bytes())
-            .with_context(|| anyhow!("failed to decode state file at {}", path.display()))?;
-
-        Ok(state)
+        match DeBin::deserialize_bin(&bytes) {
+            Ok(state) => Ok(state),
+            Err(_) => Self::load_legacy(&bytes)
+                .with_context(|| anyhow!("failed to decode state file at {}", path.display())),
+        }
     }
 
-    fn save(&self) -> Result<()> {
-        let dir = Self::save_dir();
+    /// Loads a state file from before the remote revision was tracked separately from
+    /// the update status. Tracking of the remote revision begins from the next check.
+    fn load_legacy(bytes: &[u8]) -> Result<Self> {
+        let status = DeBin::deserialize_bin(bytes)?;
+
+        Ok(Self {
+            status,
+            ..Self::default()
+        })
+    }
 
-        if !dir.exists() {
-            fs::create_dir_all(&dir).with_context(|| {
-                anyhow!("failed to create state directory at {}", dir.display())
+    fn save(&self, opts: &StateOptions) -> Result<()> {
+        if !opts.dir.exists() {
+            Self::create_dir(&opts.dir, opts.dir_mode).with_context(|| {
+                anyhow!("failed to create state directory at {}", opts.dir.display())
             })?;
         }
 
-        let mut path = dir;
-        path.push(Self::DEFAULT_FILE_NAME);
+        let path = opts.path();
 
         let contents = SerBin::serialize_bin(self);
 
-        fs::write(&path, contents)
+        write_atomic(&path, &contents)
             .with_context(|| anyhow!("failed to write state file to {}", path.display()))?;
 
+        // The JSON file is only a mirror, so failing to write it shouldn't fail the check
+        if let Some(json_path) = &opts.json_path {
+            if let Err(err) = write_atomic(json_path, self.to_json().as_bytes()) {
+                eprintln!(
+                    "warning: failed to write JSON state file to {}: {:#}",
+                    json_path.display(),
+                    err
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn to_json(&self) -> String {
+        let json = JsonState {
+            synced: self.status == UpdateState::Synced,
+            missed_updates: self.status.missed_updates(),
+            remote_rev: self.remote_rev.clone(),
+            remote_rev_changed_at: self.remote_rev_changed_at,
+            last_check_at: self.last_check_at,
+        };
+
+        json.serialize_json()
+    }
+
+    /// Creates `dir` with the given permissions. Any missing parent directories are
+    /// created with the default permissions.
+    fn create_dir(dir: &Path, mode: u32) -> io::Result<()> {
+        if let Some(parent) = dir.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        match DirBuilder::new().mode(mode).create(dir) {
+            Ok(()) => (),
+            // Another instance may have created the directory at the same time
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+            Err(err) => return Err(err),
+        }
+
+        // The mode passed to DirBuilder is restricted by the umask
+        fs::set_permissions(dir, Permissions::from_mode(mode))
+    }
+
+    /// Ensures that the state directory is owned by the current user and can't be written to
+    /// by anyone else.
+    fn check_dir_permissions(opts: &StateOptions) -> Result<()> {
+        let dir = &opts.dir;
+
+        let metadata = match fs::metadata(dir) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    anyhow!("failed to read state directory at {}", dir.display())
+                })
+            }
+        };
+
+        // SAFETY: getuid is always successful and has no side effects
+        let uid = unsafe { libc::getuid() };
+
+        if metadata.uid() != uid {
+            return Err(anyhow!(
+                "state directory at {} is not owned by the current user",
+                dir.display()
+            ));
+        }
+
+        let mode = metadata.mode() & 0o777;
+
+        if mode & !0o755 != 0 {
+            return Err(anyhow!(
+                "state directory at {} has unsafe permissions: {:o}",
+                dir.display(),
+                mode
+            ));
+        }
+
         Ok(())
     }
 
@@ -140,39 +745,516 @@ impl UpdateState {
     }
 }
 
-impl Default for UpdateState {
-    fn default() -> Self {
-        Self::Synced
+struct StateOptions {
+    dir: PathBuf,
+    file_name: String,
+    readonly: bool,
+    json_path: Option<PathBuf>,
+    dir_mode: u32,
+}
+
+impl StateOptions {
+    const DEFAULT_FILE_NAME: &'static str = "state.bin";
+
+    fn from_args(args: &Args) -> Result<Self> {
+        let file_name = match &args.state_file_name {
+            Some(name) => {
+                let path = Path::new(name);
+
+                if path.file_name() != Some(OsStr::new(name)) {
+                    return Err(anyhow!("state file name must not contain a path: {}", name));
+                }
+
+                if path.extension() != Some(OsStr::new("bin")) {
+                    return Err(anyhow!("state file name must end in .bin: {}", name));
+                }
+
+                name.clone()
+            }
+            None => Self::DEFAULT_FILE_NAME.into(),
+        };
+
+        // Comparing against an older generation shouldn't clobber the missed update count of
+        // the current system, so it only gets its own state when a separate file is named
+        let readonly = args.state_readonly
+            || (args.diff_since_generation.is_some() && args.state_file_name.is_none());
+
+        Ok(Self {
+            dir: State::save_dir(),
+            file_name,
+            readonly,
+            json_path: args.write_state_json.clone(),
+            dir_mode: args.state_dir_mode,
+        })
     }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(&self.file_name)
+    }
+}
+
+enum RemoteSource {
+    Channel {
+        name: String,
+        fallback: Option<String>,
+    },
+    NixpkgsBranch(String),
+    RevisionFile(PathBuf),
+}
+
+impl RemoteSource {
+    fn from_args(args: &Args) -> Result<Self> {
+        if args.channel_fallback.is_some() && args.channel.is_none() {
+            return Err(anyhow!(
+                "a channel fallback can only be used with a channel"
+            ));
+        }
+
+        let num_sources = [
+            args.channel.is_some(),
+            args.nixpkgs_branch.is_some(),
+            args.remote_rev_file.is_some(),
+        ]
+        .iter()
+        .filter(|&&is_set| is_set)
+        .count();
+
+        if num_sources > 1 {
+            return Err(anyhow!(
+                "only one of a channel, nixpkgs branch, or remote revision file can be tracked at a time"
+            ));
+        }
+
+        if let Some(channel) = &args.channel {
+            Ok(Self::Channel {
+                name: channel.clone(),
+                fallback: args.channel_fallback.clone(),
+            })
+        } else if let Some(branch) = &args.nixpkgs_branch {
+            Ok(Self::NixpkgsBranch(branch.clone()))
+        } else if let Some(path) = &args.remote_rev_file {
+            Ok(Self::RevisionFile(path.clone()))
+        } else {
+            Err(anyhow!(
+                "a channel, nixpkgs branch, or remote revision file must be specified"
+            ))
+        }
+    }
+
+    fn latest_revision(&self, allow_http: bool) -> Result<Revision> {
+        match self {
+            Self::Channel { name, fallback } => {
+                channel_revision(name, fallback.as_deref(), allow_http)
+            }
+            Self::NixpkgsBranch(branch) => nixpkgs_branch_revision(branch, allow_http),
+            Self::RevisionFile(path) => file_revision(path),
+        }
+    }
+}
+
+fn channel_revision(channel: &str, fallback: Option<&str>, allow_http: bool) -> Result<Revision> {
+    let url = Url::parse(&format!(
+        "https://nixos.org/channels/{channel}/git-revision"
+    ))?;
+
+    let (_, resp) = get_following_redirects(url, allow_http)?;
+
+    if !resp.is_success() {
+        if let Some(fallback) = fallback {
+            eprintln!(
+                "warning: channel {} returned {}, falling back to {}",
+                channel,
+                resp.status(),
+                fallback
+            );
+
+            return channel_revision(fallback, None, allow_http);
+        }
+
+        return Err(anyhow!("bad response: {}", resp.status()));
+    }
+
+    let rev = resp.text()?;
+
+    if rev.trim().is_empty() {
+        return Err(EmptyRevisionError.into());
+    }
+
+    Ok(rev)
+}
+
+/// Finds the URL of the tarball that contains the channel's Nix expressions.
+fn channel_tarball_url(channel: &str, allow_http: bool) -> Result<Url> {
+    let url = Url::parse(&format!("https://nixos.org/channels/{channel}/"))?;
+
+    // The final URL is needed so relative links can be resolved against the page they
+    // were found on
+    let (url, resp) = get_following_redirects(url, allow_http)?;
+
+    if !resp.is_success() {
+        return Err(anyhow!("bad response: {}", resp.status()));
+    }
+
+    let body = resp.text()?;
+
+    let href = body
+        .split("href=\"")
+        .skip(1)
+        .filter_map(|rest| rest.split('"').next())
+        .find(|href| href.ends_with(".tar.xz"))
+        .ok_or_else(|| anyhow!("no tarball found on channel page at {}", url))?;
+
+    url.join(href).map_err(Into::into)
+}
+
+/// Sends a GET request to `url`, following any redirects manually so a redirect to plain
+/// HTTP can be refused unless `allow_http` is set.
+///
+/// Returns the final URL along with its response.
+fn get_following_redirects(mut url: Url, allow_http: bool) -> Result<(Url, Response)> {
+    const MAX_REDIRECTS: usize = 10;
+
+    for _ in 0..=MAX_REDIRECTS {
+        if !allow_http && url.scheme() != "https" {
+            return Err(anyhow!(
+                "refusing to request non-HTTPS URL without --allow-http: {}",
+                url
+            ));
+        }
+
+        let resp = attohttpc::get(url.as_str())
+            .follow_redirects(false)
+            .send()?;
+
+        if !resp.status().is_redirection() {
+            return Ok((url, resp));
+        }
+
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| anyhow!("redirect from {} has no location", url))?;
+
+        url = url.join(location)?;
+    }
+
+    Err(anyhow!("too many redirects while requesting {}", url))
+}
+
+#[derive(Debug)]
+struct EmptyRevisionError;
+
+impl fmt::Display for EmptyRevisionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "channel returned an empty revision")
+    }
+}
+
+impl std::error::Error for EmptyRevisionError {}
+
+#[derive(DeJson)]
+struct GitRef {
+    object: GitObject,
+}
+
+#[derive(DeJson)]
+struct GitObject {
+    sha: String,
 }
 
-fn remote_system_revision<S>(channel: S) -> Result<String>
-where
-    S: AsRef<str>,
-{
-    let url = format!(
-        "https://nixos.org/channels/{}/git-revision",
-        channel.as_ref()
-    );
+fn nixpkgs_branch_revision(branch: &str, allow_http: bool) -> Result<Revision> {
+    let url = Url::parse(&format!(
+        "https://api.github.com/repos/NixOS/nixpkgs/git/ref/refs/heads/{branch}"
+    ))?;
 
-    let resp = attohttpc::get(url).follow_redirects(true).send()?;
+    let (_, resp) = get_following_redirects(url, allow_http)?;
 
     if !resp.is_success() {
         return Err(anyhow!("bad response: {}", resp.status()));
     }
 
-    resp.text().map_err(Into::into)
+    let body = resp.text()?;
+
+    let git_ref = GitRef::deserialize_json(&body)
+        .with_context(|| anyhow!("failed to decode GitHub response for branch {}", branch))?;
+
+    Ok(git_ref.object.sha)
+}
+
+fn file_revision(path: &Path) -> Result<Revision> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| anyhow!("failed to read revision file at {}", path.display()))?;
+
+    let rev = contents.trim_end();
+
+    if rev.len() != 40 || !rev.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!(
+            "revision file at {} does not contain a valid revision",
+            path.display()
+        ));
+    }
+
+    Ok(rev.to_string())
 }
 
-fn current_system_revision() -> Result<String> {
-    let mut cmd = Command::new("nixos-version");
+fn current_system_revision(generation: Option<u32>) -> Result<String> {
+    let mut cmd = match generation {
+        Some(generation) => {
+            let profile = format!("/nix/var/nix/profiles/system-{generation}-link");
+
+            if !Path::new(&profile).exists() {
+                return Err(anyhow!("system generation {} does not exist", generation));
+            }
+
+            Command::new(format!("{profile}/sw/bin/nixos-version"))
+        }
+        None => Command::new("nixos-version"),
+    };
+
     cmd.arg("--revision");
 
     let output = cmd
         .output()
         .context("failed to retrieve current system revision with nixos-version command")?;
 
+    if !output.status.success() {
+        return Err(anyhow!(
+            "nixos-version command failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+
     let rev = String::from_utf8(output.stdout)?;
 
     Ok(rev.trim_end().to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REVISIONS: [&str; 3] = [
+        "0000000000000000000000000000000000000000",
+        "1123456789012345678901234567890123456789",
+        "ffffffffffffffffffffffffffffffffffffffff",
+    ];
+
+    fn all_states() -> Vec<UpdateState> {
+        let missed_counts = [
+            0,
+            1,
+            2,
+            7,
+            128,
+            65_536,
+            MissedUpdates::MAX - 1,
+            MissedUpdates::MAX,
+        ];
+
+        let unsynced = missed_counts.iter().flat_map(|&missed| {
+            REVISIONS
+                .iter()
+                .map(move |rev| UpdateState::Unsynced(missed, (*rev).into()))
+        });
+
+        std::iter::once(UpdateState::Synced)
+            .chain(unsynced)
+            .collect()
+    }
+
+    struct TempStateDir(PathBuf);
+
+    impl TempStateDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!(
+                "{}-{}-{}",
+                env!("CARGO_PKG_NAME"),
+                name,
+                std::process::id()
+            ));
+
+            let _ = fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+
+        fn opts(&self) -> StateOptions {
+            StateOptions {
+                dir: self.0.clone(),
+                file_name: StateOptions::DEFAULT_FILE_NAME.into(),
+                readonly: false,
+                json_path: None,
+                dir_mode: 0o700,
+            }
+        }
+    }
+
+    impl Drop for TempStateDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn all_transitions() -> impl Iterator<Item = (UpdateState, bool, &'static str)> {
+        all_states().into_iter().flat_map(|state| {
+            [false, true].iter().flat_map(move |&is_unsynced| {
+                let state = state.clone();
+
+                REVISIONS
+                    .iter()
+                    .map(move |&remote_rev| (state.clone(), is_unsynced, remote_rev))
+            })
+        })
+    }
+
+    #[test]
+    fn synced_when_remote_matches_system() {
+        for (state, is_unsynced, remote_rev) in all_transitions().filter(|(_, u, _)| !u) {
+            assert_eq!(
+                state.next(is_unsynced, remote_rev),
+                UpdateState::Synced,
+                "from {state:?} with remote {remote_rev}"
+            );
+        }
+    }
+
+    #[test]
+    fn missed_count_unchanged_when_remote_unchanged() {
+        for (state, is_unsynced, remote_rev) in all_transitions().filter(|(_, u, _)| *u) {
+            if let UpdateState::Unsynced(_, last_rev) = &state {
+                if last_rev != remote_rev {
+                    continue;
+                }
+
+                assert_eq!(state.next(is_unsynced, remote_rev), state);
+            }
+        }
+    }
+
+    #[test]
+    fn missed_count_increments_when_remote_changes() {
+        for (state, is_unsynced, remote_rev) in all_transitions().filter(|(_, u, _)| *u) {
+            let expected = match &state {
+                UpdateState::Synced => UpdateState::Unsynced(1, remote_rev.into()),
+                UpdateState::Unsynced(_, last_rev) if last_rev == remote_rev => continue,
+                // The count stays at the maximum instead of wrapping back to zero
+                UpdateState::Unsynced(MissedUpdates::MAX, _) => {
+                    UpdateState::Unsynced(MissedUpdates::MAX, remote_rev.into())
+                }
+                UpdateState::Unsynced(missed, _) => {
+                    UpdateState::Unsynced(missed + 1, remote_rev.into())
+                }
+            };
+
+            assert_eq!(
+                state.next(is_unsynced, remote_rev),
+                expected,
+                "from {state:?} with remote {remote_rev}"
+            );
+        }
+    }
+
+    #[test]
+    fn state_save_load_round_trip() {
+        let dir = TempStateDir::new("round-trip");
+        let opts = dir.opts();
+
+        let statuses = [
+            UpdateState::Synced,
+            UpdateState::Unsynced(1, REVISIONS[1].into()),
+            UpdateState::Unsynced(300, REVISIONS[2].into()),
+        ];
+
+        for status in statuses {
+            let state = State {
+                status,
+                remote_rev: REVISIONS[1].into(),
+                remote_rev_changed_at: 1_620_000_000,
+                last_check_at: 1_620_003_600,
+            };
+
+            state.save(&opts).unwrap();
+            assert_eq!(State::load(&opts).unwrap(), state);
+        }
+    }
+
+    #[test]
+    fn legacy_state_keeps_status() {
+        let dir = TempStateDir::new("legacy");
+        let opts = dir.opts();
+
+        let status = UpdateState::Unsynced(300, REVISIONS[1].into());
+
+        fs::create_dir_all(&opts.dir).unwrap();
+        fs::write(opts.path(), SerBin::serialize_bin(&status)).unwrap();
+
+        let state = State::load(&opts).unwrap();
+
+        assert_eq!(
+            state,
+            State {
+                status,
+                ..State::default()
+            }
+        );
+    }
+
+    #[test]
+    fn iso_date_matches_known_dates() {
+        let dates = [
+            (0, "1970-01-01"),
+            (45_296, "1970-01-01"),
+            (951_827_696, "2000-02-29"),
+            (1_709_210_096, "2024-02-29"),
+            (4_102_490_096, "2100-01-01"),
+            (253_402_259_696, "9999-12-31"),
+        ];
+
+        for (timestamp, date) in dates {
+            assert_eq!(iso_date(timestamp), date, "for timestamp {timestamp}");
+        }
+    }
+
+    #[test]
+    fn state_dir_created_with_exact_mode() {
+        let dir = TempStateDir::new("dir-mode");
+
+        for mode in [0o700, 0o770, 0o777] {
+            let _ = fs::remove_dir_all(&dir.0);
+
+            State::create_dir(&dir.0, mode).unwrap();
+            let metadata = fs::metadata(&dir.0).unwrap();
+
+            assert_eq!(metadata.mode() & 0o777, mode);
+        }
+    }
+
+    #[test]
+    fn state_dir_creation_tolerates_existing_dir() {
+        let dir = TempStateDir::new("dir-exists");
+
+        State::create_dir(&dir.0, 0o700).unwrap();
+        State::create_dir(&dir.0, 0o700).unwrap();
+    }
+
+    #[test]
+    fn dir_permissions_reject_writable_by_others() {
+        let dir = TempStateDir::new("dir-permissions");
+        let opts = dir.opts();
+
+        // A directory that doesn't exist yet will be created with a safe mode later
+        State::check_dir_permissions(&opts).unwrap();
+
+        for (mode, is_safe) in [(0o700, true), (0o755, true), (0o775, false), (0o777, false)] {
+            let _ = fs::remove_dir_all(&dir.0);
+            State::create_dir(&dir.0, mode).unwrap();
+
+            assert_eq!(
+                State::check_dir_permissions(&opts).is_ok(),
+                is_safe,
+                "for mode {mode:o}"
+            );
+        }
+    }
+}